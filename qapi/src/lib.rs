@@ -16,6 +16,9 @@ pub use self::qmp_impl::*;
 #[cfg(feature = "qapi-qga")]
 pub use self::qga_impl::*;
 
+#[cfg(feature = "qapi-tokio")]
+pub use self::tokio_impl::*;
+
 #[cfg(any(feature = "qapi-qmp", feature = "qapi-qga"))]
 mod qapi {
     use serde_json;
@@ -123,15 +126,36 @@ mod stream {
 
 #[cfg(feature = "qapi-qmp")]
 mod qmp_impl {
+    use std::collections::{HashMap, VecDeque};
     use std::io::{self, BufRead, Read, Write, BufReader};
+    use std::sync::mpsc::{self, Receiver, Sender};
     use std::vec::Drain;
-    use qapi_spec::{Error, Command};
-    use qapi_qmp::{QMP, QapiCapabilities, QmpMessage, Event, qmp_capabilities, query_version};
+    use serde_json::Value;
+    use qapi_spec::{Error, Response, Command};
+    use qapi_qmp::{QMP, QMPCapability, QapiCapabilities, Event, qmp_capabilities, query_version, human_monitor_command};
     use crate::{qapi::Qapi, Stream};
 
+    // Like `QapiCapabilities::supports_oob`, this matches on the known
+    // variant rather than assuming `PartialEq` is derived on the generated
+    // `QMPCapability`.
+    fn capability_eq(a: &QMPCapability, b: &QMPCapability) -> bool {
+        match (a, b) {
+            (QMPCapability::oob, QMPCapability::oob) => true,
+        }
+    }
+
     pub struct Qmp<S> {
         inner: Qapi<S>,
         event_queue: Vec<Event>,
+        next_oob_id: u64,
+        // Buffers a response that arrived while some *other* id was being
+        // awaited, keyed by that response's own id (`None` for in-band). This
+        // is only useful when a caller interleaves `write_command` (which
+        // doesn't block on a reply) with `execute_oob`/`read_response_id` for
+        // a different id before finally reading the deferred response — see
+        // `write_command_oob`'s doc comment for the full recovery sequence.
+        pending: HashMap<Option<u64>, VecDeque<Value>>,
+        subscriptions: HashMap<String, Vec<Sender<Event>>>,
     }
 
     impl<S: Read + Write + Clone> Qmp<Stream<BufReader<S>, S>> {
@@ -145,6 +169,9 @@ mod qmp_impl {
             Qmp {
                 inner: Qapi::new(stream),
                 event_queue: Default::default(),
+                next_oob_id: 0,
+                pending: Default::default(),
+                subscriptions: Default::default(),
             }
         }
 
@@ -163,6 +190,29 @@ mod qmp_impl {
         pub fn events(&mut self) -> Drain<Event> {
             self.event_queue.drain(..)
         }
+
+        /// Subscribes to events named `event_name` (e.g. `"STOP"`, `"RESET"`,
+        /// `"SHUTDOWN"`), returning a channel fed every matching event as it is
+        /// observed, instead of having to poll `events()` after a round-trip.
+        pub fn subscribe(&mut self, event_name: &str) -> Receiver<Event> {
+            let (tx, rx) = mpsc::channel();
+            self.subscriptions.entry(event_name.to_owned()).or_default().push(tx);
+            rx
+        }
+
+        /// Drops all subscriptions for `event_name`; their receivers will see
+        /// the channel disconnect.
+        pub fn unsubscribe(&mut self, event_name: &str) {
+            self.subscriptions.remove(event_name);
+        }
+
+        fn dispatch_event(&mut self, event: Event) {
+            if let Some(senders) = self.subscriptions.get_mut(event.name()) {
+                senders.retain(|tx| tx.send(event.clone()).is_ok());
+            }
+
+            self.event_queue.push(event);
+        }
     }
 
     impl<S: BufRead> Qmp<S> {
@@ -172,16 +222,58 @@ mod qmp_impl {
             )
         }
 
-        pub fn read_response<C: Command>(&mut self) -> io::Result<Result<C::Ok, Error>> {
+        fn parse_response<C: Command>(value: Value) -> io::Result<Result<C::Ok, Error>> {
+            serde_json::from_value::<Response<C::Ok>>(value)
+                .map(Response::result)
+                .map_err(From::from)
+        }
+
+        /// Reads the next response matching `id` (`None` for an in-band
+        /// command), buffering any other id's response until its own awaiter
+        /// calls this with a matching `id`. This client is blocking and
+        /// single-threaded, so two commands are never outstanding from a
+        /// single combined call like `execute`/`execute_oob` alone — the
+        /// buffer is only exercised by interleaving `write_command` with a
+        /// differently-id'd `read_response_id` call before reading the first
+        /// command's response, as described on `write_command_oob`.
+        pub fn read_response_id<C: Command>(&mut self, id: Option<u64>) -> io::Result<Result<C::Ok, Error>> {
+            if let Some(value) = self.pending.get_mut(&id).and_then(VecDeque::pop_front) {
+                return Self::parse_response::<C>(value);
+            }
+
             loop {
-                match self.inner.decode_line()? {
+                // Decode each line once, as a raw `Value`: QEMU echoes the
+                // request's `id` as a top-level field of the reply object
+                // itself (sibling to `return`/`error`), regardless of whether
+                // `qapi_spec::Response` surfaces it, so read it straight off
+                // the wire rather than through a type we don't control.
+                let value: Value = match self.inner.decode_line()? {
                     None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "expected command response")),
-                    Some(QmpMessage::Greeting(..)) => return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected greeting")),
-                    Some(QmpMessage::Response(res)) => return Ok(res.result()),
-                    Some(QmpMessage::Event(e)) => self.event_queue.push(e),
+                    Some(v) => v,
+                };
+
+                if value.get("QMP").is_some() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected greeting"));
+                }
+
+                if value.get("event").is_some() {
+                    let event: Event = serde_json::from_value(value)?;
+                    self.dispatch_event(event);
+                    continue;
                 }
+
+                let res_id = value.get("id").and_then(Value::as_u64);
+                if res_id == id {
+                    return Self::parse_response::<C>(value);
+                }
+
+                self.pending.entry(res_id).or_default().push_back(value);
             }
         }
+
+        pub fn read_response<C: Command>(&mut self) -> io::Result<Result<C::Ok, Error>> {
+            self.read_response_id::<C>(None)
+        }
     }
 
     impl<S: BufRead + Write> Qmp<S> {
@@ -189,16 +281,81 @@ mod qmp_impl {
             self.inner.write_command(command)
         }
 
+        /// Writes `command` as an out-of-band `exec-oob` request tagged with a
+        /// fresh, monotonically increasing `id`, returning that id so the
+        /// matching response can be read with `read_response_id`.
+        ///
+        /// To recover a stuck monitor while a slow in-band command is still
+        /// outstanding: `write_command(&slow)` (doesn't block on a reply),
+        /// then `execute_oob(&recover)` to send and read the OOB response out
+        /// of turn — if the slow command's reply arrives first it is buffered
+        /// by `read_response_id` rather than mistaken for the OOB one — and
+        /// finally `read_response::<Slow>()` once ready to pick up its
+        /// (possibly already-buffered) reply.
+        pub fn write_command_oob<C: Command>(&mut self, command: &C) -> io::Result<u64> {
+            let id = self.next_oob_id;
+            self.next_oob_id += 1;
+
+            let mut value = serde_json::to_value(qapi_spec::CommandSerializerRef(command))?;
+            if let Some(obj) = value.as_object_mut() {
+                if let Some(execute) = obj.remove("execute") {
+                    obj.insert("exec-oob".into(), execute);
+                }
+                obj.insert("id".into(), id.into());
+            }
+
+            serde_json::to_writer(&mut self.inner.stream, &value)?;
+            self.inner.stream.write(&[b'\n'])?;
+            self.inner.stream.flush()
+                .map(|()| id)
+        }
+
         pub fn execute<C: Command>(&mut self, command: &C) -> io::Result<Result<C::Ok, Error>> {
             self.write_command(command)?;
             self.read_response::<C>()
         }
 
+        /// Executes `command` out-of-band via `exec-oob`, which QEMU may
+        /// service ahead of any slow in-band command still awaiting its
+        /// response. Requires the server to have advertised (and the
+        /// connection to have negotiated) the `oob` capability.
+        pub fn execute_oob<C: Command>(&mut self, command: &C) -> io::Result<Result<C::Ok, Error>> {
+            let id = self.write_command_oob(command)?;
+            self.read_response_id::<C>(Some(id))
+        }
+
+        /// Negotiates the QMP handshake, enabling every capability the
+        /// greeting advertises (e.g. `oob`, once `QapiCapabilities::capabilities()`
+        /// reports it).
+        ///
+        /// Breaking change: this used to negotiate with `enable: None`, i.e.
+        /// no capabilities. Enabling everything the server offers by default
+        /// means `oob` gets turned on whenever the server supports it, which
+        /// permits QEMU to reorder command responses — use `handshake_with`
+        /// with an explicit (possibly empty) list to keep the old behavior.
         pub fn handshake(&mut self) -> io::Result<QMP> {
-            let caps = self.read_capabilities()?;
-            self.execute(&qmp_capabilities { enable: None })
+            let greeting = self.read_capabilities()?;
+            let caps = QapiCapabilities { QMP: greeting.clone() }.capabilities();
+            self.negotiate(greeting, caps)
+        }
+
+        /// Negotiates the QMP handshake, requesting only `caps` be enabled.
+        /// Fails if the greeting did not advertise one of the requested
+        /// capabilities.
+        pub fn handshake_with(&mut self, caps: Vec<QMPCapability>) -> io::Result<QMP> {
+            let greeting = self.read_capabilities()?;
+            let supported = QapiCapabilities { QMP: greeting.clone() }.capabilities();
+            if let Some(unsupported) = caps.iter().find(|c| !supported.iter().any(|s| capability_eq(s, c))) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("capability {:?} not advertised by greeting", unsupported)));
+            }
+
+            self.negotiate(greeting, caps)
+        }
+
+        fn negotiate(&mut self, greeting: QMP, caps: Vec<QMPCapability>) -> io::Result<QMP> {
+            self.execute(&qmp_capabilities { enable: Some(caps) })
                 .and_then(|v| v.map_err(From::from))
-                .map(|_| caps)
+                .map(|_| greeting)
         }
 
         /// Can be used to poll the socket for pending events
@@ -207,6 +364,16 @@ mod qmp_impl {
                 .and_then(|v| v.map_err(From::from))
                 .map(drop)
         }
+
+        /// Runs `command_line` as a Human Monitor Protocol command (e.g.
+        /// `"info block"`, `"info registers"`) and returns its plain-text
+        /// output, for diagnostics that only exist as HMP commands.
+        pub fn execute_hmp(&mut self, command_line: &str) -> io::Result<Result<String, Error>> {
+            self.execute(&human_monitor_command {
+                command_line: command_line.into(),
+                cpu_index: None,
+            })
+        }
     }
 }
 
@@ -282,3 +449,243 @@ mod qga_impl {
         }
     }
 }
+
+#[cfg(feature = "qapi-tokio")]
+mod tokio_impl {
+    use std::collections::VecDeque;
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use bytes::BytesMut;
+    use futures::lock::BiLock;
+    use futures::{SinkExt, Stream, StreamExt};
+    use serde::Serialize;
+    use serde_json;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_util::codec::{Decoder, Encoder, Framed};
+    use qapi_spec::{self, Command, Error};
+    use qapi_qmp::{Event, QmpMessage, QMP, QapiCapabilities, qmp_capabilities};
+
+    /// Frames a QMP/QGA socket into newline-terminated JSON messages.
+    #[derive(Default)]
+    pub struct Codec;
+
+    impl Decoder for Codec {
+        type Item = String;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<String>> {
+            match src.iter().position(|b| *b == b'\n') {
+                Some(n) => {
+                    let line = src.split_to(n + 1);
+                    Ok(Some(String::from_utf8_lossy(&line[..n]).into_owned()))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+
+    impl<C: Command> Encoder<&C> for Codec {
+        type Error = io::Error;
+
+        fn encode(&mut self, command: &C, dst: &mut BytesMut) -> io::Result<()> {
+            let mut ser = serde_json::Serializer::new(Vec::new());
+            qapi_spec::CommandSerializerRef(command).serialize(&mut ser)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            dst.extend_from_slice(&ser.into_inner());
+            dst.extend_from_slice(b"\n");
+            Ok(())
+        }
+    }
+
+    type Transport<S> = Framed<S, Codec>;
+
+    /// The shared state behind a split async QMP transport: the framed
+    /// socket plus a line demuxed by content, not by who reads it next.
+    ///
+    /// Both `QmpEvents` and `QapiFuture` take turns locking this (via
+    /// `BiLock`) to pull the next line off the wire, but neither assumes the
+    /// line it reads belongs to it: a `Response` read by the events half is
+    /// queued in `responses` for the awaiting `QapiFuture`, and an `Event`
+    /// read by the future half is queued in `events` for `QmpEvents`. This is
+    /// what makes the split safe despite the two sides racing for the lock.
+    struct Demuxed<S> {
+        transport: Transport<S>,
+        events: VecDeque<Event>,
+        responses: VecDeque<String>,
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> Demuxed<S> {
+        /// Reads and classifies exactly one frame, queuing it under `events`
+        /// or `responses` for whichever side is looking for it.
+        fn poll_pump(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+            match self.transport.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(line))) => match serde_json::from_str(&line) {
+                    Ok(QmpMessage::<qapi_spec::Any>::Event(e)) => {
+                        self.events.push_back(e);
+                        Poll::Ready(Ok(()))
+                    }
+                    Ok(QmpMessage::Response(..)) => {
+                        self.responses.push_back(line);
+                        Poll::Ready(Ok(()))
+                    }
+                    Err(e) => Poll::Ready(Err(e.into())),
+                },
+                Poll::Ready(Some(Err(e))) => Poll::Ready(Err(e)),
+                Poll::Ready(None) => Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "transport closed"))),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    /// The event half of a split async QMP transport.
+    ///
+    /// Yields every `Event` observed on the socket, whether it arrives
+    /// between commands or interleaved with an in-flight `QapiFuture`'s
+    /// response.
+    pub struct QmpEvents<S> {
+        shared: BiLock<Demuxed<S>>,
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> Stream for QmpEvents<S> {
+        type Item = io::Result<Event>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+            let this = self.get_mut();
+            let mut shared = match this.shared.poll_lock(cx) {
+                Poll::Ready(s) => s,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            loop {
+                if let Some(event) = shared.events.pop_front() {
+                    return Poll::Ready(Some(Ok(event)));
+                }
+
+                match shared.poll_pump(cx) {
+                    Poll::Ready(Ok(())) => continue,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+    }
+
+    /// A future resolving a single in-band QMP command to its response.
+    ///
+    /// Events observed while awaiting the response are queued on the shared
+    /// `Demuxed` state for the sibling `QmpEvents` half to pick up.
+    pub struct QapiFuture<'a, C, S> {
+        command: Option<C>,
+        shared: &'a mut BiLock<Demuxed<S>>,
+    }
+
+    impl<'a, C: Command, S: AsyncRead + AsyncWrite + Unpin> QapiFuture<'a, C, S> {
+        pub fn new(command: C, shared: &'a mut BiLock<Demuxed<S>>) -> Self {
+            QapiFuture {
+                command: Some(command),
+                shared,
+            }
+        }
+    }
+
+    impl<'a, C: Command + Unpin, S: AsyncRead + AsyncWrite + Unpin> Future for QapiFuture<'a, C, S> {
+        type Output = io::Result<Result<C::Ok, Error>>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let this = self.get_mut();
+            let mut shared = match this.shared.poll_lock(cx) {
+                Poll::Ready(s) => s,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if this.command.is_some() {
+                match shared.transport.poll_ready_unpin(cx) {
+                    Poll::Ready(Ok(())) => {}
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+
+                let command = this.command.take().expect("checked above");
+                if let Err(e) = shared.transport.start_send_unpin(&command) {
+                    return Poll::Ready(Err(e));
+                }
+            }
+
+            if let Poll::Ready(Err(e)) = shared.transport.poll_flush_unpin(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            loop {
+                if let Some(line) = shared.responses.pop_front() {
+                    return match serde_json::from_str::<QmpMessage<C::Ok>>(&line) {
+                        Ok(QmpMessage::Response(res)) => Poll::Ready(Ok(res.result())),
+                        Ok(QmpMessage::Event(..)) => unreachable!("only responses are queued in `responses`"),
+                        Err(e) => Poll::Ready(Err(e.into())),
+                    };
+                }
+
+                match shared.poll_pump(cx) {
+                    Poll::Ready(Ok(())) => continue,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+    }
+
+    /// An async QMP client driven from a tokio reactor, mirroring `Qmp`.
+    pub struct Qmp<S> {
+        shared: BiLock<Demuxed<S>>,
+    }
+
+    impl<S: AsyncRead + AsyncWrite> Qmp<S> {
+        /// Wraps a connected socket, returning the client and its paired event
+        /// stream so events can be consumed independently of command execution.
+        /// Call `handshake` (or `read_capabilities`) on the client before using
+        /// either half: QEMU's greeting is not itself an `Event` or `Response`
+        /// and must be consumed separately first.
+        pub fn new(stream: S) -> (Self, QmpEvents<S>) {
+            let demuxed = Demuxed {
+                transport: Framed::new(stream, Codec),
+                events: Default::default(),
+                responses: Default::default(),
+            };
+            let (a, b) = BiLock::new(demuxed);
+            (Qmp { shared: a }, QmpEvents { shared: b })
+        }
+    }
+
+    impl<S: AsyncRead + AsyncWrite + Unpin> Qmp<S> {
+        pub fn execute<C: Command>(&mut self, command: C) -> QapiFuture<'_, C, S> {
+            QapiFuture::new(command, &mut self.shared)
+        }
+
+        /// Reads the QMP greeting QEMU sends as the first line after
+        /// connecting. `Demuxed::poll_pump` only knows how to classify
+        /// `Event`/`Response` frames, so this (or `handshake`) must complete
+        /// before the first `execute` call or any `QmpEvents` polling, or the
+        /// greeting will fail to parse as either and error out the exchange.
+        pub async fn read_capabilities(&mut self) -> io::Result<QMP> {
+            let mut shared = self.shared.lock().await;
+            let line = shared.transport.next().await
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected eof"))??;
+
+            serde_json::from_str::<QapiCapabilities>(&line)
+                .map(|caps| caps.QMP)
+                .map_err(From::from)
+        }
+
+        /// Reads the greeting and negotiates the QMP handshake, mirroring the
+        /// sync `Qmp::handshake`. Must be called once, before any other use
+        /// of this client or its paired `QmpEvents`.
+        pub async fn handshake(&mut self) -> io::Result<QMP> {
+            let greeting = self.read_capabilities().await?;
+            let caps = QapiCapabilities { QMP: greeting.clone() }.capabilities();
+            self.execute(qmp_capabilities { enable: Some(caps) }).await
+                .and_then(|v| v.map_err(From::from))
+                .map(|_| greeting)
+        }
+    }
+}